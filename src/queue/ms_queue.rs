@@ -1,12 +1,25 @@
+use std::cell::UnsafeCell;
+use std::fmt;
 use std::mem::{self, ManuallyDrop};
 use std::ptr;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
+use std::sync::Arc;
 use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
 
 use epoch::{self, Atomic, Owned, Shared};
 use utils::CachePadded;
 
+/// A blocked request is `PENDING` until a producer claims it; it then moves
+/// to `CLAIMED` while the data is being written, and finally `DONE` once the
+/// waiter may read it. A waiter that gives up before a producer claims its
+/// node instead moves it straight from `PENDING` to `CANCELLED`.
+const PENDING: usize = 0;
+const CLAIMED: usize = 1;
+const DONE: usize = 2;
+const CANCELLED: usize = 3;
+
 /// A Michael-Scott lock-free queue, with support for blocking `pop`s.
 ///
 /// Usable with any number of producers and consumers.
@@ -31,20 +44,41 @@ enum Payload<T> {
     /// A node with actual data that can be popped.
     Data(ManuallyDrop<T>),
     /// A node representing a blocked request for data.
-    Blocked(*mut Signal<T>),
+    Blocked(Arc<Signal<T>>),
 }
 
 /// A blocked request for data, which includes a slot to write the data.
-#[derive(Debug)]
+///
+/// This is kept alive in an `Arc` (rather than on the waiter's stack, as a
+/// plain `Signal`) so that a producer racing to deliver data to it can keep
+/// touching it even after the waiter has timed out and its stack frame has
+/// returned.
 struct Signal<T> {
     /// Thread to unpark when data is ready.
     thread: Thread,
-    /// The actual data, when available.
-    data: Option<T>,
-    /// Is the data ready? Needed to cope with spurious wakeups.
-    ready: AtomicBool,
+    /// The actual data, when available. Only written by the producer that
+    /// wins the `PENDING -> CLAIMED` race, and only read by the waiter once
+    /// it observes `DONE`.
+    data: UnsafeCell<Option<T>>,
+    /// `PENDING`, `CLAIMED`, `DONE`, or `CANCELLED`; see the consts above.
+    state: AtomicUsize,
+}
+
+impl<T> fmt::Debug for Signal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Signal")
+            .field("thread", &self.thread)
+            .field("state", &self.state.load(Relaxed))
+            .finish()
+    }
 }
 
+// The `UnsafeCell<Option<T>>` is written only by the producer that wins the
+// `PENDING -> CLAIMED` race and read only by the waiter after it observes
+// `DONE`, so `state` orders every access; the value only moves from one
+// thread to another, so `T: Send` is enough.
+unsafe impl<T: Send> Sync for Signal<T> {}
+
 impl<T> Node<T> {
     fn is_data(&self) -> bool {
         if let Payload::Data(_) = self.payload {
@@ -170,7 +204,7 @@ impl<T> MsQueue<T> {
                 // be empty or in data mode by the time we see it.
                 let next_shared = head.next.load(Acquire, &guard);
                 let request = unsafe { next_shared.as_ref() }.and_then(|next| match next.payload {
-                    Payload::Blocked(signal) => Some((next_shared, signal)),
+                    Payload::Blocked(ref signal) => Some((next_shared, signal.clone())),
                     Payload::Data(_) => None,
                 });
                 if let Some((blocked_node, signal)) = request {
@@ -180,15 +214,26 @@ impl<T> MsQueue<T> {
                         .compare_and_set(head_shared, blocked_node, Release, &guard)
                         .is_ok()
                     {
-                        unsafe {
-                            // signal the thread
-                            (*signal).data = Some(cache.into_data());
-                            let thread = (*signal).thread.clone();
-
-                            (*signal).ready.store(true, Release);
+                        // Race the waiter to claim the node: if it already
+                        // cancelled, the node is a tombstone and must not
+                        // consume our data, so go look elsewhere.
+                        if signal
+                            .state
+                            .compare_exchange(PENDING, CLAIMED, AcqRel, Acquire)
+                            .is_ok()
+                        {
+                            unsafe {
+                                *signal.data.get() = Some(cache.into_data());
+                                guard.defer_destroy(head_shared);
+                            }
+                            let thread = signal.thread.clone();
+                            signal.state.store(DONE, Release);
                             thread.unpark();
-                            guard.defer_destroy(head_shared);
                             return;
+                        } else {
+                            unsafe {
+                                guard.defer_destroy(head_shared);
+                            }
                         }
                     }
                 }
@@ -253,55 +298,50 @@ impl<T> MsQueue<T> {
         }
     }
 
-    /// Dequeue an element from the front of the queue, blocking if the queue is
-    /// empty.
-    pub fn pop(&self) -> T {
-        let guard = epoch::pin();
-
+    /// Try to pop without blocking; if the queue is empty, link a blocked
+    /// request node into the list instead and hand back its signal so the
+    /// caller can wait on it.
+    fn pop_or_register(&self, guard: &epoch::Guard) -> Result<T, Arc<Signal<T>>> {
         // Fast path: keep retrying until we observe that the queue has no data,
         // avoiding the allocation of a blocked node.
         loop {
-            match self.pop_internal(&guard) {
-                Ok(Some(r)) => {
-                    return r;
-                }
-                Ok(None) => {
-                    break;
-                }
+            match self.pop_internal(guard) {
+                Ok(Some(r)) => return Ok(r),
+                Ok(None) => break,
                 Err(()) => {}
             }
         }
 
-        // The signal gets to live on the stack, since this stack frame will be
-        // blocked until receiving the signal.
-        let mut signal = Signal {
+        // The signal is kept alive in an `Arc`, since a producer may still be
+        // racing to deliver data to it after our caller gives up waiting.
+        let signal = Arc::new(Signal {
             thread: thread::current(),
-            data: None,
-            ready: AtomicBool::new(false),
-        };
+            data: UnsafeCell::new(None),
+            state: AtomicUsize::new(PENDING),
+        });
 
         // Go ahead and allocate the blocked node; chances are, we'll need it.
         let mut node = Owned::new(Node {
-            payload: Payload::Blocked(&mut signal),
+            payload: Payload::Blocked(signal.clone()),
             next: Atomic::null(),
         });
 
         loop {
             // try a normal pop
-            if let Ok(Some(r)) = self.pop_internal(&guard) {
-                return r;
+            if let Ok(Some(r)) = self.pop_internal(guard) {
+                return Ok(r);
             }
 
             // At this point, we believe the queue is empty/blocked.
             // Snapshot the tail, onto which we want to push a blocked node.
-            let tail_shared = self.tail.load(Acquire, &guard);
+            let tail_shared = self.tail.load(Acquire, guard);
             let tail = unsafe { tail_shared.as_ref() }.unwrap();
 
             // Double-check that we're in blocking mode
             if tail.is_data() {
                 // The current tail is in data mode, so we probably need to abort.
                 // BUT, it might be the sentinel, so check for that first.
-                let head_shared = self.head.load(Relaxed, &guard);
+                let head_shared = self.head.load(Relaxed, guard);
                 if tail.is_data() && tail_shared != head_shared {
                     continue;
                 }
@@ -313,20 +353,94 @@ impl<T> MsQueue<T> {
             // snapshot, we know we are maintaining the core invariant: all
             // reachable, non-sentinel nodes have the same payload mode, in this
             // case, blocked.
-            match self.push_internal(&guard, tail_shared, node) {
-                Ok(()) => break,
+            match self.push_internal(guard, tail_shared, node) {
+                Ok(()) => return Err(signal),
                 Err(n) => {
                     node = n;
                 }
             }
         }
+    }
+
+    /// Dequeue an element from the front of the queue, blocking if the queue is
+    /// empty.
+    pub fn pop(&self) -> T {
+        let guard = epoch::pin();
+        let signal = match self.pop_or_register(&guard) {
+            Ok(r) => return r,
+            Err(signal) => signal,
+        };
 
+        // The signal gets to live on, since we may return before a producer
+        // finishes delivering data to it; the `Arc` keeps it alive.
         drop(guard);
 
-        while !signal.ready.load(Acquire) {
+        loop {
+            if signal.state.load(Acquire) == DONE {
+                return unsafe { (*signal.data.get()).take() }.unwrap();
+            }
             thread::park();
         }
-        signal.data.unwrap()
+    }
+
+    /// Dequeue an element from the front of the queue, blocking for up to
+    /// `timeout` if the queue is empty.
+    ///
+    /// Returns `None` if no element became available before `timeout` elapsed.
+    /// If a producer has already claimed this call's request when `timeout`
+    /// elapses, the call waits past `timeout` for that producer to finish and
+    /// returns its element.
+    pub fn pop_timeout(&self, timeout: Duration) -> Option<T> {
+        self.pop_deadline(Instant::now() + timeout)
+    }
+
+    /// Dequeue an element from the front of the queue, blocking until
+    /// `deadline` if the queue is empty.
+    ///
+    /// Returns `None` if no element became available before `deadline` passed.
+    /// If a producer has already claimed this call's request when `deadline`
+    /// passes, the call waits past `deadline` for that producer to finish and
+    /// returns its element.
+    pub fn pop_deadline(&self, deadline: Instant) -> Option<T> {
+        let guard = epoch::pin();
+        let signal = match self.pop_or_register(&guard) {
+            Ok(r) => return Some(r),
+            Err(signal) => signal,
+        };
+
+        drop(guard);
+
+        loop {
+            if signal.state.load(Acquire) == DONE {
+                return unsafe { (*signal.data.get()).take() };
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            thread::park_timeout(deadline - now);
+        }
+
+        // We timed out. Try to cancel our own request before a producer gets
+        // to it; the node stays linked into the list as a tombstone that the
+        // next producer to reach it will skip over and reclaim.
+        match signal
+            .state
+            .compare_exchange(PENDING, CANCELLED, AcqRel, Acquire)
+        {
+            Ok(_) => None,
+            Err(_) => {
+                // A producer already raced us to `CLAIMED` and is writing our
+                // data; wait for it to finish so we don't drop the value.
+                loop {
+                    if signal.state.load(Acquire) == DONE {
+                        return unsafe { (*signal.data.get()).take() };
+                    }
+                    thread::park();
+                }
+            }
+        }
     }
 }
 
@@ -554,4 +668,57 @@ mod test {
         assert!(!q.is_empty());
         assert!(q.try_pop().is_some());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn pop_timeout_empty() {
+        let q: MsQueue<i64> = MsQueue::new();
+        assert_eq!(q.pop_timeout(Duration::from_millis(50)), None);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn pop_timeout_then_push() {
+        let q: MsQueue<i64> = MsQueue::new();
+        assert_eq!(q.pop_timeout(Duration::from_millis(50)), None);
+        q.push(7);
+        assert_eq!(q.pop_timeout(Duration::from_secs(1)), Some(7));
+    }
+
+    #[test]
+    fn pop_timeout_woken_by_push() {
+        let q: MsQueue<i64> = MsQueue::new();
+
+        scope(|scope| {
+            scope.spawn(|_| {
+                thread::sleep(Duration::from_millis(50));
+                q.push(99);
+            });
+
+            assert_eq!(q.pop_timeout(Duration::from_secs(5)), Some(99));
+        }).unwrap();
+    }
+
+    #[test]
+    fn pop_timeout_races_with_push() {
+        // Exercise the `PENDING -> CANCELLED` / `PENDING -> CLAIMED` race: the
+        // timeout is short enough that many waiters give up right around the
+        // time a producer might be trying to deliver to them.
+        let q: MsQueue<i64> = MsQueue::new();
+
+        scope(|scope| {
+            scope.spawn(|_| {
+                for i in 0..1000 {
+                    thread::sleep(Duration::from_micros(50));
+                    q.push(i);
+                }
+            });
+
+            let mut received = 0;
+            while received < 1000 {
+                if q.pop_timeout(Duration::from_micros(10)).is_some() {
+                    received += 1;
+                }
+            }
+        }).unwrap();
+    }
+}